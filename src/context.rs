@@ -0,0 +1,103 @@
+use crossbeam_channel::Sender;
+use fnv::FnvHashMap;
+use jsonrpc_core::{self, Call, Id, MethodCall, Output, Params, Version};
+use request_queue::{cancel_request, OutgoingRequests, PendingRequest};
+use serde::Serialize;
+use serde_json::{self, Value};
+use types::*;
+
+// Shared state threaded through the language feature handlers.
+pub struct Context {
+    pub sender: Sender<ServerMessage>,
+    pub editor_sender: Sender<(EditorMeta, String)>,
+    // Requests we've asked the editor about, keyed by the id we handed to
+    // the language server.
+    pub response_waitlist: FnvHashMap<Id, (EditorMeta, String, EditorParams)>,
+    // Requests we've sent to the language server and are still waiting on an
+    // answer for.
+    pub outgoing: OutgoingRequests,
+    // Settings last pushed via didChangeConfiguration; see
+    // workspace::workspace_configuration.
+    pub last_config_settings: Value,
+    pub root_path: String,
+    request_counter: u64,
+}
+
+impl Context {
+    pub fn new(
+        root_path: String,
+        sender: Sender<ServerMessage>,
+        editor_sender: Sender<(EditorMeta, String)>,
+    ) -> Self {
+        Context {
+            sender,
+            editor_sender,
+            response_waitlist: FnvHashMap::default(),
+            outgoing: OutgoingRequests::default(),
+            last_config_settings: Value::Null,
+            root_path,
+            request_counter: 0,
+        }
+    }
+
+    pub fn next_request_id(&mut self) -> Id {
+        self.request_counter += 1;
+        Id::Num(self.request_counter)
+    }
+
+    // Registers the request in `outgoing` (using the meta already stashed in
+    // `response_waitlist` for `id`) before sending it.
+    pub fn call<R: Serialize>(&mut self, id: Id, method: String, params: R) {
+        if let Some((meta, _, _)) = self.response_waitlist.get(&id) {
+            self.outgoing.insert(
+                id.clone(),
+                PendingRequest {
+                    method: method.clone(),
+                    meta: meta.clone(),
+                },
+            );
+        }
+
+        let params = Params::from(serde_json::to_value(params).expect("Failed to serialize params"));
+        let call = Call::MethodCall(MethodCall {
+            jsonrpc: Some(Version::V2),
+            method,
+            params,
+            id,
+        });
+        self.sender.send(ServerMessage::Request(call));
+    }
+
+    pub fn notify<R: Serialize>(&self, method: String, params: R) {
+        let params = Params::from(serde_json::to_value(params).expect("Failed to serialize params"));
+        let notification = jsonrpc_core::Notification {
+            jsonrpc: Some(Version::V2),
+            method,
+            params: Some(params),
+        };
+        self.sender
+            .send(ServerMessage::Request(Call::Notification(notification)));
+    }
+
+    pub fn reply(&self, output: Output) {
+        self.sender.send(ServerMessage::Response(output));
+    }
+
+    pub fn exec(&self, meta: EditorMeta, command: String) {
+        self.editor_sender.send((meta, command));
+    }
+
+    // Called from the response-dispatch path when a matching Output arrives.
+    pub fn complete(&mut self, id: &Id) -> Option<PendingRequest> {
+        self.outgoing.remove(id)
+    }
+
+    // Abandons a request we no longer care about via $/cancelRequest, so an
+    // eventual Output for `id` is dropped instead of reaching response_waitlist.
+    pub fn cancel(&mut self, id: Id) {
+        if self.outgoing.remove(&id).is_some() {
+            cancel_request(id.clone(), &self.sender);
+        }
+        self.response_waitlist.remove(&id);
+    }
+}
@@ -1,63 +1,427 @@
-use crossbeam_channel::{bounded, Receiver, Sender};
+use crossbeam_channel::{bounded, select, Receiver, RecvTimeoutError, Sender};
 use fnv::FnvHashMap;
-use jsonrpc_core::{self, Call, Output, Params, Version};
+use jsonrpc_core::{self, Call, Id, MethodCall, Output, Params, Version};
 use lsp_types::notification::Notification;
+use lsp_types::request::{Request, Shutdown};
 use lsp_types::*;
+use serde::Deserialize;
 use serde_json;
+use std::cmp;
 use std::io::{self, BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use types::*;
 
+// NOTE a server that hasn't acknowledged `shutdown` by the time this elapses
+// is assumed to be stuck; we fall back to killing it outright
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// NOTE backoff schedule for `start_supervised`: 1s, 2s, 4s, ... capped at
+// MAX_BACKOFF, giving up when it goes down again after MAX_RETRIES restart
+// attempts in a row. Retries reset
+// once the server has stayed up for HEALTHY_AFTER, so a server that crashes
+// once a day doesn't slowly exhaust its budget.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_RETRIES: u32 = 5;
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+
 pub struct LanguageServerTransport {
     pub sender: Sender<ServerMessage>,
     pub receiver: Receiver<ServerMessage>,
     pub thread: thread::JoinHandle<()>,
+    kill: Arc<Mutex<Box<Fn() + Send>>>,
+    shutting_down: Arc<AtomicBool>,
+}
+
+impl LanguageServerTransport {
+    // Drives the LSP shutdown handshake: send `shutdown`, wait for its
+    // response (bounded by SHUTDOWN_TIMEOUT total, not reset by unrelated
+    // traffic), then send `exit`. Falls back to killing the child outright
+    // if the server doesn't acknowledge. Any other message received while
+    // waiting is returned instead of dropped, so the caller can still
+    // dispatch it.
+    pub fn shutdown(&self) -> Vec<ServerMessage> {
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        let id = Id::Str("kak-lsp-shutdown".to_string());
+        let request = Call::MethodCall(MethodCall {
+            jsonrpc: Some(Version::V2),
+            method: Shutdown::METHOD.to_string(),
+            params: Params::None,
+            id: id.clone(),
+        });
+        debug!("Sending shutdown request to language server");
+        self.sender.send(ServerMessage::Request(request));
+
+        let mut unmatched = Vec::new();
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        let acknowledged = loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break false;
+            }
+            let remaining = deadline.duration_since(now);
+            match self.receiver.recv_timeout(remaining) {
+                Ok(ServerMessage::Response(Output::Success(success))) if success.id == id => {
+                    break true;
+                }
+                Ok(ServerMessage::Response(Output::Failure(failure))) if failure.id == id => {
+                    break true;
+                }
+                Ok(msg) => {
+                    unmatched.push(msg);
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) => break false,
+                Err(RecvTimeoutError::Disconnected) => break false,
+            }
+        };
+
+        if !acknowledged {
+            warn!("Language server didn't acknowledge shutdown, killing it");
+            (self.kill.lock().unwrap())();
+            return unmatched;
+        }
+
+        let notification = jsonrpc_core::Notification {
+            jsonrpc: Some(Version::V2),
+            method: notification::Exit::METHOD.to_string(),
+            params: Some(Params::None),
+        };
+        debug!("Sending exit notification to language server");
+        self.sender
+            .send(ServerMessage::Request(Call::Notification(notification)));
+        unmatched
+    }
 }
 
 pub fn start(cmd: &str, args: &[String]) -> LanguageServerTransport {
+    start_internal(cmd, args).0
+}
+
+// Same as `start`, but also returns a handle onto the tail of the language
+// server's stderr (its last non-empty line), so `start_supervised` has something more useful to show
+// on an unexpected exit than "it died".
+fn start_internal(cmd: &str, args: &[String]) -> (LanguageServerTransport, Arc<Mutex<String>>) {
+    let kill = Arc::new(Mutex::new(Box::new(|| {}) as Box<Fn() + Send>));
+    start_internal_with_kill(cmd, args, kill).expect("Failed to start language server")
+}
+
+// Same as `start_internal`, but writes the child's kill function into a kill
+// cell the caller already holds instead of minting a fresh one, so
+// `start_supervised` can keep one kill cell alive across restarts.
+fn start_internal_with_kill(
+    cmd: &str,
+    args: &[String],
+    kill: Arc<Mutex<Box<Fn() + Send>>>,
+) -> io::Result<(LanguageServerTransport, Arc<Mutex<String>>)> {
     info!("Starting Language server `{} {}`", cmd, args.join(" "));
     let mut child = Command::new(cmd)
         .args(args)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .spawn()
-        .expect("Failed to start language server");
+        .spawn()?;
 
     let writer = BufWriter::new(child.stdin.take().expect("Failed to open stdin"));
     let reader = BufReader::new(child.stdout.take().expect("Failed to open stdout"));
 
     // XXX temporary way of tracing language server errors
     let mut stderr = BufReader::new(child.stderr.take().expect("Failed to open stderr"));
-    let error_thread = thread::spawn(move || loop {
-        let mut buf = String::new();
-        match stderr.read_to_string(&mut buf) {
-            Ok(_) => {
-                if buf.is_empty() {
+    let stderr_tail = Arc::new(Mutex::new(String::new()));
+    let error_thread = {
+        let stderr_tail = Arc::clone(&stderr_tail);
+        thread::spawn(move || loop {
+            let mut buf = String::new();
+            match stderr.read_to_string(&mut buf) {
+                Ok(_) => {
+                    if buf.is_empty() {
+                        return;
+                    }
+                    error!("Language server error: {}", buf);
+                    *stderr_tail.lock().unwrap() = buf
+                        .lines()
+                        .rev()
+                        .find(|line| !line.trim().is_empty())
+                        .unwrap_or("")
+                        .to_string();
+                }
+                Err(e) => {
+                    error!("Failed to read from language server stderr: {}", e);
                     return;
                 }
-                error!("Language server error: {}", buf);
             }
-            Err(e) => {
-                error!("Failed to read from language server stderr: {}", e);
-                return;
+        })
+    };
+    // XXX
+
+    let child = Arc::new(Mutex::new(child));
+
+    let wait_child = Arc::clone(&child);
+    let (reader_tx, reader_rx) = new_message_channel();
+    let reader_thread = spawn_reader_thread(reader, reader_tx, move || {
+        // NOTE prevent zombie
+        debug!("Waiting for language server process end");
+        if wait_child.lock().unwrap().wait().is_err() {
+            error!("Language server wasn't running was it?!");
+        }
+    });
+
+    let (writer_tx, writer_rx) = new_message_channel();
+    let writer_thread = spawn_writer_thread(writer, writer_rx);
+
+    let thread = spawn_rendezvous_thread(Some(error_thread), reader_thread, writer_thread);
+
+    let kill_child = Arc::clone(&child);
+    *kill.lock().unwrap() = Box::new(move || {
+        if kill_child.lock().unwrap().kill().is_err() {
+            error!("Failed to kill language server");
+        }
+    });
+    let transport = LanguageServerTransport {
+        sender: writer_tx,
+        receiver: reader_rx,
+        thread,
+        kill,
+        shutting_down: Arc::new(AtomicBool::new(false)),
+    };
+    Ok((transport, stderr_tail))
+}
+
+// Connects to a language server exposed over TCP instead of spawning a child
+// process, for debugging setups and remote dev environments.
+pub fn start_tcp(addr: &str) -> LanguageServerTransport {
+    info!("Connecting to language server at `{}`", addr);
+    let stream = TcpStream::connect(addr).expect("Failed to connect to language server");
+    let kill_stream = stream.try_clone().expect("Failed to clone TCP stream");
+    let writer = BufWriter::new(stream.try_clone().expect("Failed to clone TCP stream"));
+    let reader = BufReader::new(stream);
+
+    let (reader_tx, reader_rx) = new_message_channel();
+    let reader_thread = spawn_reader_thread(reader, reader_tx, || {
+        // NOTE there is no child process to reap, the socket closing is the
+        // only signal we get that the language server went away
+        debug!("Language server socket closed");
+    });
+
+    let (writer_tx, writer_rx) = new_message_channel();
+    let writer_thread = spawn_writer_thread(writer, writer_rx);
+
+    let thread = spawn_rendezvous_thread(None, reader_thread, writer_thread);
+
+    LanguageServerTransport {
+        sender: writer_tx,
+        receiver: reader_rx,
+        thread,
+        // NOTE there is no child process to kill; shut down both halves of
+        // the socket so the reader/writer threads unblock and exit instead
+        // of waiting on a connection nobody is going to use again
+        kill: Arc::new(Mutex::new(Box::new(move || {
+            if let Err(e) = kill_stream.shutdown(std::net::Shutdown::Both) {
+                error!("Failed to shut down language server TCP connection: {}", e);
+            }
+        }))),
+        shutting_down: Arc::new(AtomicBool::new(false)),
+    }
+}
+
+// A language server entry from the user's config: either a command to spawn
+// or a `tcp = "host:port"` address of a server that's already running.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum LanguageServerConfig {
+    Command {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    Tcp {
+        tcp: String,
+    },
+}
+
+pub fn start_from_config(config: &LanguageServerConfig) -> LanguageServerTransport {
+    match config {
+        LanguageServerConfig::Command { command, args } => start(command, args),
+        LanguageServerConfig::Tcp { tcp } => start_tcp(tcp),
+    }
+}
+
+// Wraps `start` with a supervisor that respawns the language server after an
+// unexpected exit. The returned transport is stable across restarts: the
+// caller keeps using the same sender/receiver while the process underneath
+// is swapped out. `on_restart` runs after a respawn so the controller can
+// re-send `initialize`/`didOpen`; `on_status` gets a one-line message for
+// `ctx.exec` when a recovery happens (or we give up).
+pub fn start_supervised(
+    cmd: String,
+    args: Vec<String>,
+    on_restart: impl Fn() + Send + 'static,
+    on_status: impl Fn(String) + Send + 'static,
+) -> LanguageServerTransport {
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    let supervisor_shutting_down = Arc::clone(&shutting_down);
+
+    // NOTE shared across restarts so a `kill` taken on the transport we
+    // return always reaches whichever child process is currently running,
+    // instead of being wired to one that's already been replaced
+    let kill = Arc::new(Mutex::new(Box::new(|| {}) as Box<Fn() + Send>));
+    let supervisor_kill = Arc::clone(&kill);
+
+    let (to_server, from_controller) = new_message_channel();
+    let (to_controller, from_server) = new_message_channel();
+
+    let thread = thread::spawn(move || {
+        let (mut inner, mut stderr_tail) =
+            start_internal_with_kill(&cmd, &args, Arc::clone(&supervisor_kill))
+                .expect("Failed to start language server");
+        let mut retries = 0;
+        let mut last_restart = Instant::now();
+
+        loop {
+            match pump(&inner, &from_controller, &to_controller) {
+                PumpResult::ControllerGone => break,
+                PumpResult::Exited => {
+                    if supervisor_shutting_down.load(Ordering::SeqCst) {
+                        debug!("Language server `{}` exited after shutdown", cmd);
+                        send_exit(&to_controller);
+                        break;
+                    }
+
+                    if last_restart.elapsed() > HEALTHY_AFTER {
+                        retries = 0;
+                    }
+
+                    // A failed respawn attempt (missing binary, fork/exec
+                    // exhaustion, ...) counts against retries just like a
+                    // crash, instead of unwinding this thread.
+                    let restarted = loop {
+                        if retries >= MAX_RETRIES {
+                            break None;
+                        }
+
+                        let backoff = backoff_for(retries);
+                        retries += 1;
+                        warn!(
+                            "Language server `{}` exited unexpectedly, restarting in {:?} (attempt {})",
+                            cmd, backoff, retries
+                        );
+                        thread::sleep(backoff);
+
+                        match start_internal_with_kill(&cmd, &args, Arc::clone(&supervisor_kill)) {
+                            Ok(started) => break Some(started),
+                            Err(e) => error!("Failed to restart language server `{}`: {}", cmd, e),
+                        }
+                    };
+
+                    match restarted {
+                        Some((new_inner, new_stderr_tail)) => {
+                            inner = new_inner;
+                            stderr_tail = new_stderr_tail;
+                            last_restart = Instant::now();
+
+                            on_status(format!("language server `{}` restarted", cmd));
+                            on_restart();
+                        }
+                        None => {
+                            let tail = stderr_tail.lock().unwrap().clone();
+                            error!(
+                                "Language server `{}` still down after {} restart attempts in a row, giving up: {}",
+                                cmd, retries, tail
+                            );
+                            on_status(format!(
+                                "language server `{}` crashed repeatedly and was not restarted: {}",
+                                cmd, tail
+                            ));
+                            send_exit(&to_controller);
+                            break;
+                        }
+                    }
+                }
             }
         }
     });
-    // XXX
 
-    // NOTE 1024 is arbitrary
-    let (reader_tx, reader_rx) = bounded(1024);
-    let reader_thread = thread::spawn(move || {
+    LanguageServerTransport {
+        sender: to_server,
+        receiver: from_server,
+        thread,
+        kill,
+        shutting_down,
+    }
+}
+
+// Backoff before the `attempt`-th restart in a row (0-indexed).
+fn backoff_for(attempt: u32) -> Duration {
+    cmp::min(INITIAL_BACKOFF * 2u32.pow(attempt), MAX_BACKOFF)
+}
+
+enum PumpResult {
+    Exited,
+    ControllerGone,
+}
+
+/// Forwards messages between the controller-facing proxy channels and the
+/// currently running `inner` transport until `inner` reports it has exited
+/// (the synthetic `Exit` notification, or the channel simply disconnecting)
+/// or the controller drops its end.
+fn pump(
+    inner: &LanguageServerTransport,
+    from_controller: &Receiver<ServerMessage>,
+    to_controller: &Sender<ServerMessage>,
+) -> PumpResult {
+    loop {
+        select! {
+            recv(from_controller) -> msg => match msg {
+                Ok(msg) => {
+                    inner.sender.send(msg);
+                }
+                Err(_) => return PumpResult::ControllerGone,
+            },
+            recv(inner.receiver) -> msg => match msg {
+                Ok(ServerMessage::Request(Call::Notification(ref notification)))
+                    if notification.method == notification::Exit::METHOD =>
+                {
+                    return PumpResult::Exited;
+                }
+                Ok(msg) => {
+                    to_controller.send(msg);
+                }
+                Err(_) => return PumpResult::Exited,
+            },
+        }
+    }
+}
+
+fn send_exit(to_controller: &Sender<ServerMessage>) {
+    let notification = jsonrpc_core::Notification {
+        jsonrpc: Some(Version::V2),
+        method: notification::Exit::METHOD.to_string(),
+        params: Some(Params::None),
+    };
+    to_controller.send(ServerMessage::Request(Call::Notification(notification)));
+}
+
+// NOTE 1024 is arbitrary
+fn new_message_channel() -> (Sender<ServerMessage>, Receiver<ServerMessage>) {
+    bounded(1024)
+}
+
+fn spawn_reader_thread(
+    reader: impl BufRead + Send + 'static,
+    reader_tx: Sender<ServerMessage>,
+    on_eof: impl FnOnce() + Send + 'static,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
         if let Err(msg) = reader_loop(reader, &reader_tx) {
             error!("{}", msg);
         }
-        // NOTE prevent zombie
-        debug!("Waiting for language server process end");
-        if child.wait().is_err() {
-            error!("Language server wasn't running was it?!");
-        }
+        on_eof();
 
         let notification = jsonrpc_core::Notification {
             jsonrpc: Some(Version::V2),
@@ -66,35 +430,53 @@ pub fn start(cmd: &str, args: &[String]) -> LanguageServerTransport {
         };
         debug!("Sending exit notification back to controller");
         reader_tx.send(ServerMessage::Request(Call::Notification(notification)));
-    });
+    })
+}
 
-    // NOTE 1024 is arbitrary
-    let (writer_tx, writer_rx): (Sender<ServerMessage>, Receiver<ServerMessage>) = bounded(1024);
-    let writer_thread = thread::spawn(move || {
+fn spawn_writer_thread(
+    writer: impl Write + Send + 'static,
+    writer_rx: Receiver<ServerMessage>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
         if writer_loop(writer, &writer_rx).is_err() {
             error!("Failed to write message to language server");
         }
         // NOTE we rely on assumption that if write failed then read is failed as well
         // or will fail shortly and do all exiting stuff
-    });
+    })
+}
 
-    let rendezvous = thread::spawn(move || {
-        if error_thread.join().is_err() {
-            error!("Language server error monitoring thread panicked");
-        };
+fn spawn_rendezvous_thread(
+    error_thread: Option<thread::JoinHandle<()>>,
+    reader_thread: thread::JoinHandle<()>,
+    writer_thread: thread::JoinHandle<()>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        if let Some(error_thread) = error_thread {
+            if error_thread.join().is_err() {
+                error!("Language server error monitoring thread panicked");
+            };
+        }
         if reader_thread.join().is_err() {
             error!("Language server reader thread panicked");
         };
         if writer_thread.join().is_err() {
             error!("Language server writer thread panicked");
         };
-    });
+    })
+}
 
-    LanguageServerTransport {
-        sender: writer_tx,
-        receiver: reader_rx,
-        thread: rendezvous,
-    }
+/// An inbound message, disambiguated from its shape alone: a response has a
+/// `result`/`error`, a notification has no `id`, and a server-initiated
+/// request has both a `method` and an `id`. Parsing this once instead of
+/// trying `Output` and falling back to `Call` means a message that matches
+/// neither produces one parse error instead of two.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Message {
+    Output(Output),
+    Notification(jsonrpc_core::Notification),
+    Call(MethodCall),
 }
 
 fn reader_loop(mut reader: impl BufRead, tx: &Sender<ServerMessage>) -> io::Result<()> {
@@ -111,11 +493,19 @@ fn reader_loop(mut reader: impl BufRead, tx: &Sender<ServerMessage>) -> io::Resu
             if header.is_empty() {
                 break;
             }
-            let parts: Vec<&str> = header.split(": ").collect();
-            if parts.len() != 2 {
-                return Err(Error::new(ErrorKind::Other, "Failed to parse header"));
-            }
-            headers.insert(parts[0].to_string(), parts[1].to_string());
+            // NOTE split on the first `:` rather than requiring the exact
+            // "Key: Value" spacing some servers skip, and don't choke on
+            // headers we don't care about (e.g. Content-Type)
+            let mut parts = header.splitn(2, ':');
+            let name = parts.next().unwrap_or("").trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => {
+                    warn!("Ignoring malformed header: {:?}", header);
+                    continue;
+                }
+            };
+            headers.insert(name.to_string(), value.to_string());
         }
         let content_len = headers
             .get("Content-Length")
@@ -127,15 +517,14 @@ fn reader_loop(mut reader: impl BufRead, tx: &Sender<ServerMessage>) -> io::Resu
         let msg = String::from_utf8(content)
             .map_err(|_| Error::new(ErrorKind::Other, "Failed to read content as UTF-8 string"))?;
         debug!("From server: {}", msg);
-        let output: serde_json::Result<Output> = serde_json::from_str(&msg);
-        match output {
-            Ok(output) => tx.send(ServerMessage::Response(output)),
-            Err(_) => {
-                let msg: Call = serde_json::from_str(&msg).map_err(|_| {
-                    Error::new(ErrorKind::Other, "Failed to parse language server message")
-                })?;
-                tx.send(ServerMessage::Request(msg));
+        let message: Message = serde_json::from_str(&msg)
+            .map_err(|_| Error::new(ErrorKind::Other, "Failed to parse language server message"))?;
+        match message {
+            Message::Output(output) => tx.send(ServerMessage::Response(output)),
+            Message::Notification(notification) => {
+                tx.send(ServerMessage::Request(Call::Notification(notification)))
             }
+            Message::Call(call) => tx.send(ServerMessage::Request(Call::MethodCall(call))),
         }
     }
 }
@@ -160,3 +549,82 @@ fn writer_loop(mut writer: impl Write, rx: &Receiver<ServerMessage>) -> io::Resu
     debug!("Received signal to stop language server, closing pipe");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn framed(body: &str, header: &str) -> String {
+        format!("{}\r\n\r\n{}", header, body)
+    }
+
+    #[test]
+    fn reader_loop_tolerates_odd_headers() {
+        // No space after the colon, and an extra header we don't care about.
+        let notification = r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{}}"#;
+        let input = framed(
+            notification,
+            &format!(
+                "Content-Length:{}\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8",
+                notification.len()
+            ),
+        );
+
+        let (tx, rx) = bounded(1024);
+        reader_loop(Cursor::new(input.into_bytes()), &tx).unwrap();
+
+        match rx.try_recv().unwrap() {
+            ServerMessage::Request(Call::Notification(n)) => {
+                assert_eq!(n.method, "textDocument/publishDiagnostics");
+            }
+            _ => panic!("expected a notification"),
+        }
+    }
+
+    #[test]
+    fn reader_loop_disambiguates_message_shapes() {
+        let call = r#"{"jsonrpc":"2.0","id":1,"method":"workspace/configuration","params":{}}"#;
+        let notification = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let response = r#"{"jsonrpc":"2.0","id":1,"result":{}}"#;
+
+        let mut input = String::new();
+        for body in &[call, notification, response] {
+            input.push_str(&framed(body, &format!("Content-Length: {}", body.len())));
+        }
+
+        let (tx, rx) = bounded(1024);
+        reader_loop(Cursor::new(input.into_bytes()), &tx).unwrap();
+
+        match rx.try_recv().unwrap() {
+            ServerMessage::Request(Call::MethodCall(call)) => {
+                assert_eq!(call.method, "workspace/configuration");
+            }
+            _ => panic!("expected a method call first"),
+        }
+        match rx.try_recv().unwrap() {
+            ServerMessage::Request(Call::Notification(n)) => {
+                assert_eq!(n.method, "exit");
+            }
+            _ => panic!("expected a notification second"),
+        }
+        match rx.try_recv().unwrap() {
+            ServerMessage::Response(Output::Success(success)) => {
+                assert_eq!(success.id, Id::Num(1));
+            }
+            _ => panic!("expected a response third"),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        assert_eq!(backoff_for(0), Duration::from_secs(1));
+        assert_eq!(backoff_for(1), Duration::from_secs(2));
+        assert_eq!(backoff_for(2), Duration::from_secs(4));
+        assert_eq!(backoff_for(3), Duration::from_secs(8));
+        assert_eq!(backoff_for(4), Duration::from_secs(16));
+        // 2^5 = 32s would exceed MAX_BACKOFF, so it's capped at 30s.
+        assert_eq!(backoff_for(5), MAX_BACKOFF);
+        assert_eq!(backoff_for(10), MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,32 @@
+use crossbeam_channel::Sender;
+use fnv::FnvHashMap;
+use jsonrpc_core::{self, Call, Id, Params, Version};
+use serde_json;
+use types::*;
+
+// A request we've sent to the language server and are still waiting on, so
+// it can later be abandoned via `$/cancelRequest`.
+pub struct PendingRequest {
+    pub method: String,
+    pub meta: EditorMeta,
+}
+
+// Keyed by the request id we handed to the language server.
+pub type OutgoingRequests = FnvHashMap<Id, PendingRequest>;
+
+// The language server may still answer, but the caller is expected to have
+// dropped its `OutgoingRequests` entry for `id`, so the eventual `Output` is
+// dropped on arrival.
+pub fn cancel_request(id: Id, sender: &Sender<ServerMessage>) {
+    let params = serde_json::json!({ "id": id })
+        .as_object()
+        .expect("cancelRequest params are always an object")
+        .clone();
+    let notification = jsonrpc_core::Notification {
+        jsonrpc: Some(Version::V2),
+        method: "$/cancelRequest".to_string(),
+        params: Some(Params::Map(params)),
+    };
+    debug!("Cancelling request {:?}", id);
+    sender.send(ServerMessage::Request(Call::Notification(notification)));
+}
@@ -1,4 +1,5 @@
 use context::*;
+use jsonrpc_core::{Id, MethodCall, Output, Params, Success, Version};
 use lsp_types::request::Request;
 use lsp_types::*;
 use serde::Deserialize;
@@ -80,12 +81,66 @@ pub fn did_change_configuration(params: EditorParams, ctx: &mut Context) {
         }
     }
 
+    // Stashed away so a later `workspace/configuration` pull request can be
+    // answered from the same settings we just pushed, without asking
+    // Kakoune to resend them.
+    ctx.last_config_settings = Value::Object(settings.clone());
+
     let params = DidChangeConfigurationParams {
         settings: Value::Object(settings),
     };
     ctx.notify(notification::DidChangeConfiguration::METHOD.into(), params);
 }
 
+/// Answers a server-initiated `workspace/configuration` request. Servers that
+/// only support the pull model block on this during initialization, so it
+/// must be answered even if we have nothing more to say than `null`.
+pub fn workspace_configuration(id: Id, params: Params, ctx: &mut Context) {
+    let params: ConfigurationParams = match params.parse() {
+        Ok(params) => params,
+        Err(e) => {
+            error!("Failed to parse workspace/configuration params: {}", e);
+            return;
+        }
+    };
+
+    let result: Vec<Value> = params
+        .items
+        .iter()
+        .map(|item| {
+            let section = match &item.section {
+                Some(section) => section,
+                None => return Value::Null,
+            };
+            section
+                .split('.')
+                .fold(Some(&ctx.last_config_settings), |value, key| {
+                    value.and_then(|value| value.get(key))
+                })
+                .cloned()
+                .unwrap_or(Value::Null)
+        })
+        .collect();
+
+    ctx.reply(Output::Success(Success {
+        jsonrpc: Some(Version::V2),
+        result: Value::Array(result),
+        id,
+    }));
+}
+
+/// Routes a server-initiated request by method name. Called from the
+/// controller's main dispatch loop whenever a `Call::MethodCall` arrives from
+/// the language server.
+pub fn dispatch_server_request(call: MethodCall, ctx: &mut Context) {
+    match call.method.as_str() {
+        request::WorkspaceConfiguration::METHOD => {
+            workspace_configuration(call.id, call.params, ctx)
+        }
+        _ => warn!("Unhandled server-initiated request: {}", call.method),
+    }
+}
+
 pub fn workspace_symbol(meta: &EditorMeta, params: EditorParams, ctx: &mut Context) {
     let req_params = WorkspaceSymbolParams::deserialize(params.clone());
     if req_params.is_err() {